@@ -4,12 +4,29 @@
 
 extern crate bindgen;
 extern crate cc;
+#[cfg(feature = "cmake")]
+extern crate cmake;
+#[cfg(test)]
+extern crate regex;
 
+use std::collections::hash_map::DefaultHasher;
 use std::env;
+use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
 use std::ffi::{OsStr, OsString};
+use std::fs;
 use std::process::{Command, Stdio};
 
+// Shared with the crate's own lib target (`src/lib.rs` declares the same
+// module without `#[path]`), so the logic in here gets real `cargo test`
+// coverage instead of living in a `#[cfg(test)]` block that `cargo test`
+// never compiles for build scripts.
+#[path = "src/build_support.rs"]
+mod build_support;
+use build_support::{
+    bindings_cache_key, parse_bindgen_flags, parse_locked_package_version, CONSTIFIED_ENUM_PATTERNS,
+};
+
 fn main() {
     build_jsapi();
     build_jsglue();
@@ -56,9 +73,101 @@ fn cc_flags() -> Vec<&'static str> {
     result
 }
 
+/// Additional `clang_arg`s for the bindgen invocation, read from the file
+/// named by the `MOZJS_EXTRA_BINDGEN_FLAGS` env var, if set.
+fn extra_bindgen_flags() -> Vec<String> {
+    println!("cargo:rerun-if-env-changed=MOZJS_EXTRA_BINDGEN_FLAGS");
+
+    let path = match env::var_os("MOZJS_EXTRA_BINDGEN_FLAGS") {
+        Some(path) => PathBuf::from(path),
+        None => return vec![],
+    };
+
+    println!("cargo:rerun-if-changed={}", path.display());
+
+    let contents = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e));
+
+    parse_bindgen_flags(&contents)
+}
+
+/// The header bindgen is pointed at via `.header(...)`; also included by
+/// `src/jsglue.cpp`, which `build_jsglue` compiles into the `jsglue` static lib.
+const JSGLUE_HEADER: &'static str = "src/jsglue.hpp";
+
+/// Hashes the current contents of `JSGLUE_HEADER`, so editing it busts
+/// `bindings_cache_key` the same way changing `cc_flags()` or `TARGET` does.
+fn jsglue_header_hash() -> u64 {
+    let contents = fs::read(JSGLUE_HEADER)
+        .unwrap_or_else(|e| panic!("Failed to read {} for cache keying: {}", JSGLUE_HEADER, e));
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Hashes this file's own source, plus `src/build_support.rs`'s, so editing
+/// the `WHITELIST_*`/`OPAQUE_TYPES`/`BLACKLIST_TYPES`/`MODULE_RAW_LINES`/
+/// `CONSTIFIED_ENUM_PATTERNS` bindgen config below busts `bindings_cache_key`
+/// the same way changing `JSGLUE_HEADER` does, instead of silently serving
+/// a stale cached `jsapi.rs`.
+fn build_config_hash() -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for path in &["build.rs", "src/build_support.rs"] {
+        let contents = fs::read(path)
+            .unwrap_or_else(|e| panic!("Failed to read {} for cache keying: {}", path, e));
+        contents.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// A manually-bumped fallback identifier, used in place of the locked
+/// `bindgen` version when one can't be determined (see `bindgen_version`).
+const BINDINGS_CACHE_EPOCH: &'static str = "1";
+
+/// The locked `bindgen` version, read out of the nearest `Cargo.lock` found
+/// by walking up from `CARGO_MANIFEST_DIR`, so bumping the `bindgen`
+/// dependency busts `bindings_cache_key` automatically. Falls back to
+/// `BINDINGS_CACHE_EPOCH` when no `Cargo.lock` can be found.
+fn bindgen_version() -> String {
+    find_locked_package_version("bindgen")
+        .unwrap_or_else(|| format!("epoch-{}", BINDINGS_CACHE_EPOCH))
+}
+
+/// Walks upward from `CARGO_MANIFEST_DIR` looking for a `Cargo.lock`, and
+/// returns the locked version of `name` from the first one found.
+fn find_locked_package_version(name: &str) -> Option<String> {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    for dir in manifest_dir.ancestors() {
+        if let Ok(lockfile) = fs::read_to_string(dir.join("Cargo.lock")) {
+            if let Some(version) = parse_locked_package_version(&lockfile, name) {
+                return Some(version);
+            }
+        }
+    }
+    None
+}
+
+/// Where cached, configuration-keyed bindings live across `cargo build`
+/// invocations. `OUT_DIR` itself is unique per-invocation, so the cache
+/// lives a few directories up, alongside the crate's other build artifacts.
+fn bindings_cache_dir(out_dir: &PathBuf) -> PathBuf {
+    let profile_dir = out_dir.ancestors().nth(3).unwrap_or(out_dir.as_path());
+    profile_dir.join("mozjs-bindgen-cache")
+}
+
 fn build_jsapi() {
     let out_dir = env::var("OUT_DIR").unwrap();
-    let target = env::var("TARGET").unwrap();
+
+    if cfg!(feature = "cmake") {
+        build_jsapi_with_cmake(&out_dir);
+    } else {
+        build_jsapi_with_make(&out_dir);
+    }
+}
+
+/// Build via the `makefile.cargo` invocation this crate has always used.
+/// Requires a `make` (or `mozmake`/`gmake`) on `PATH`.
+fn build_jsapi_with_make(out_dir: &str) {
     let mut make = find_make();
     // Put MOZTOOLS_PATH at the beginning of PATH if specified
     if let Some(moztools) = env::var_os("MOZTOOLS_PATH") {
@@ -88,8 +197,39 @@ fn build_jsapi() {
         .expect("Failed to run `make`");
 
     assert!(result.success());
+    println!("cargo:outdir={}", out_dir);
+
     println!("cargo:rustc-link-search=native={}/js/src", out_dir);
     println!("cargo:rustc-link-lib=static=js_static"); // Must come before c++
+    link_cxx_stdlib();
+}
+
+/// Build via `cmake`, mirroring the upstream gecko `build.rs`. Selected by
+/// the `cmake` cargo feature for platforms without the MozillaBuild/MSYS
+/// make toolchain that `build_jsapi_with_make` depends on.
+#[cfg(feature = "cmake")]
+fn build_jsapi_with_cmake(out_dir: &str) {
+    let dest = cmake::Config::new(".").build();
+    println!("cargo:outdir={}", out_dir);
+
+    println!("cargo:rustc-link-search=native={}/lib", dest.display());
+    println!("cargo:rustc-link-lib=static=js_static"); // Must come before c++
+    link_cxx_stdlib();
+}
+
+#[cfg(not(feature = "cmake"))]
+fn build_jsapi_with_cmake(_out_dir: &str) {
+    unreachable!("the `cmake` feature must be enabled to use the cmake build path");
+}
+
+/// The C++ standard library the platform's default compiler links
+/// against, which `js_static` itself was built against and so must be
+/// present whenever it is linked in, plus the `winmm` and `psapi` system
+/// libraries on Windows targets. Shared by both backends in
+/// `build_jsapi_with_make`/`build_jsapi_with_cmake`, since it doesn't
+/// depend on which one produced `js_static`.
+fn link_cxx_stdlib() {
+    let target = env::var("TARGET").unwrap();
     if target.contains("windows") {
         println!("cargo:rustc-link-lib=winmm");
         println!("cargo:rustc-link-lib=psapi");
@@ -101,7 +241,6 @@ fn build_jsapi() {
     } else {
         println!("cargo:rustc-link-lib=stdc++");
     }
-    println!("cargo:outdir={}", out_dir);
 }
 
 
@@ -127,6 +266,34 @@ fn build_jsglue() {
 /// generated, see the `const` configuration variables below.
 fn build_jsapi_bindings() {
     let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let target = env::var("TARGET").unwrap();
+
+    // Emitted unconditionally, ahead of the cache check below, so that
+    // cargo keeps tracking `jsglue.hpp` as a rebuild trigger even on a
+    // cache hit (printing this only on the generate path that a cache hit
+    // returns before reaching would silently drop it from the watch set).
+    println!("cargo:rerun-if-changed={}", JSGLUE_HEADER);
+
+    let extra_flags = extra_bindgen_flags();
+    let bindgen_version = bindgen_version();
+    let jsglue_hash = jsglue_header_hash();
+    let build_config_hash = build_config_hash();
+    let cache_dir = bindings_cache_dir(&out);
+    let cache_key = bindings_cache_key(
+        &target,
+        &cc_flags(),
+        &extra_flags,
+        &bindgen_version,
+        jsglue_hash,
+        build_config_hash,
+    );
+    let cached_bindings = cache_dir.join(format!("jsapi-{}.rs", cache_key));
+
+    if cached_bindings.is_file() {
+        fs::copy(&cached_bindings, out.join("jsapi.rs"))
+            .expect("Should copy cached JSAPI bindings OK");
+        return;
+    }
 
     // By default, constructors, destructors and methods declared in .h files are inlined,
     // so their symbols aren't available. Adding the -fkeep-inlined-functions option
@@ -138,10 +305,15 @@ fn build_jsapi_bindings() {
     
     let mut builder = bindgen::builder()
         .rust_target(bindgen::RustTarget::Stable_1_19)
-        .header("./src/jsglue.hpp")
-        // Translate every enum with the "rustified enum" strategy. We should
-        // investigate switching to the "constified module" strategy, which has
-        // similar ergonomics but avoids some potential Rust UB footguns.
+        .header(JSGLUE_HEADER)
+        // Most enums are closed sets of values and get the "rustified enum"
+        // strategy. Enums whose C++ values are combined bitwise (flags like
+        // `JSITER_*`/`JSPROP_*`) can't be represented that way without
+        // triggering UB on arbitrary bit combinations, so those are
+        // constified instead (`pub type X = u32;` plus `pub const`
+        // variants); see `CONSTIFIED_ENUM_PATTERNS` below. bindgen checks
+        // `constified_enum_module` matches before `rustified_enum` ones, so
+        // listing a pattern there takes priority over this catch-all.
         .rustified_enum(".*")
         .enable_cxx_namespaces()
         .with_codegen_config(config)
@@ -152,10 +324,18 @@ fn build_jsapi_bindings() {
         builder = builder.clang_arg("-fms-compatibility");
     }
 
+    for pattern in CONSTIFIED_ENUM_PATTERNS {
+        builder = builder.constified_enum_module(*pattern);
+    }
+
     for flag in cc_flags() {
         builder = builder.clang_arg(flag);
     }
 
+    for flag in extra_flags {
+        builder = builder.clang_arg(flag);
+    }
+
     println!("Generting bindings {:?}.", builder.command_line_flags());
 
     for ty in UNSAFE_IMPL_SYNC_TYPES {
@@ -192,7 +372,9 @@ fn build_jsapi_bindings() {
     bindings.write_to_file(out.join("jsapi.rs"))
         .expect("Should write bindings to file OK");
 
-    println!("cargo:rerun-if-changed=src/jsglue.hpp");
+    fs::create_dir_all(&cache_dir).expect("Should create bindings cache dir OK");
+    fs::copy(out.join("jsapi.rs"), &cached_bindings)
+        .expect("Should populate bindings cache OK");
 }
 
 /// JSAPI types for which we should implement `Sync`.
@@ -276,3 +458,4 @@ const MODULE_RAW_LINES: &'static [(&'static str, &'static str)] = &[
     ("root::JS", "pub type Heap<T> = ::jsgc::Heap<T>;"),
     ("root::JS", "pub type AutoGCRooterTag = AutoGCRooter__bindgen_ty_1;"),
 ];
+
@@ -7,28 +7,144 @@
 use glue;
 use jsapi;
 use rust::Runtime;
+use std::io;
+use std::marker::PhantomData;
+use std::os::raw::c_void;
 use std::ptr;
 
+/// A Rust-y hook for teaching `StructuredCloneBuffer` how to clone host
+/// objects the structured clone algorithm doesn't know about by itself.
+pub trait StructuredCloneCallbacks {
+    /// Called when the reader encounters a `tag` it doesn't understand
+    /// natively, with the bytes written for it by `write_object`. Return
+    /// the deserialized object, or `None` to signal failure.
+    fn read_tag(&mut self,
+                cx: *mut jsapi::JSContext,
+                tag: u32,
+                data: &[u8])
+                -> Option<*mut jsapi::JSObject>;
+
+    /// Called when the writer encounters an object it doesn't know how to
+    /// clone natively. Serialize `obj` through `w` and return `true`, or
+    /// return `false` to signal failure. Must call `w.write_pair(tag,
+    /// data)` exactly once, with `data` set to the byte length of exactly
+    /// one subsequent `w.write_bytes(...)` call — see `write_pair`.
+    fn write_object(&mut self,
+                    obj: jsapi::JS::HandleObject,
+                    w: &mut StructuredCloneWriter)
+                    -> bool;
+
+    /// Called when the structured clone algorithm hits an unrecoverable
+    /// error, so the implementation can report it however is appropriate
+    /// for the embedding.
+    fn report_error(&mut self, msg: &str);
+}
+
+/// A thin, borrowed handle onto the in-progress `JSStructuredCloneWriter`,
+/// handed to `StructuredCloneCallbacks::write_object` so it can append its
+/// own tag and payload to the clone stream.
+pub struct StructuredCloneWriter<'a> {
+    raw: *mut jsapi::JSStructuredCloneWriter,
+    _marker: PhantomData<&'a mut jsapi::JSStructuredCloneWriter>,
+}
+
+impl<'a> StructuredCloneWriter<'a> {
+    /// Write a `(tag, data)` pair identifying the kind of object that
+    /// follows. `data` must be the byte length of exactly one subsequent
+    /// `write_bytes` call, which the reader uses to know how much to read
+    /// back for `read_tag`.
+    pub fn write_pair(&mut self, tag: u32, data: u32) -> bool {
+        unsafe {
+            jsapi::JS_WriteUint32Pair(self.raw, tag, data)
+        }
+    }
+
+    /// Append raw bytes to the clone stream.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> bool {
+        unsafe {
+            jsapi::JS_WriteBytes(self.raw, bytes.as_ptr() as *const c_void, bytes.len())
+        }
+    }
+}
+
+unsafe extern "C" fn read_callback(cx: *mut jsapi::JSContext,
+                                    r: *mut jsapi::JSStructuredCloneReader,
+                                    tag: u32,
+                                    data: u32,
+                                    closure: *mut c_void)
+                                    -> *mut jsapi::JSObject {
+    let callbacks = &mut *(closure as *mut Box<dyn StructuredCloneCallbacks>);
+
+    let mut bytes = vec![0u8; data as usize];
+    if !bytes.is_empty() &&
+       !jsapi::JS_ReadBytes(r, bytes.as_mut_ptr() as *mut c_void, bytes.len()) {
+        return ptr::null_mut();
+    }
+
+    match callbacks.read_tag(cx, tag, &bytes) {
+        Some(obj) => obj,
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn write_callback(_cx: *mut jsapi::JSContext,
+                                     w: *mut jsapi::JSStructuredCloneWriter,
+                                     obj: jsapi::JS::HandleObject,
+                                     closure: *mut c_void)
+                                     -> bool {
+    let callbacks = &mut *(closure as *mut Box<dyn StructuredCloneCallbacks>);
+    let mut writer = StructuredCloneWriter {
+        raw: w,
+        _marker: PhantomData,
+    };
+    callbacks.write_object(obj, &mut writer)
+}
+
+unsafe extern "C" fn report_error_callback(_cx: *mut jsapi::JSContext,
+                                            errorid: u32,
+                                            closure: *mut c_void) {
+    let callbacks = &mut *(closure as *mut Box<dyn StructuredCloneCallbacks>);
+    callbacks.report_error(&format!("structured clone error {}", errorid));
+}
+
 /// An RAII owned buffer for structured cloning into and out of.
 pub struct StructuredCloneBuffer {
     raw: *mut jsapi::JSAutoStructuredCloneBuffer,
+    vtable: Box<jsapi::JSStructuredCloneCallbacks>,
+    closure: *mut Box<dyn StructuredCloneCallbacks>,
 }
 
 impl StructuredCloneBuffer {
-    /// Construct a new `StructuredCloneBuffer`.
+    /// Construct a new `StructuredCloneBuffer` backed by `callbacks`,
+    /// which it owns and consults whenever the structured clone algorithm
+    /// needs to read or write a host object it doesn't understand on its
+    /// own.
     ///
     /// # Panics
     ///
     /// Panics if the underlying JSAPI calls fail.
     pub fn new(scope: jsapi::JS::StructuredCloneScope,
-               callbacks: &jsapi::JSStructuredCloneCallbacks)
+               callbacks: Box<dyn StructuredCloneCallbacks>)
                -> StructuredCloneBuffer {
+        let closure = Box::into_raw(Box::new(callbacks));
+        let vtable = Box::new(jsapi::JSStructuredCloneCallbacks {
+            read: Some(read_callback),
+            write: Some(write_callback),
+            reportError: Some(report_error_callback),
+            readTransfer: None,
+            writeTransfer: None,
+            freeTransfer: None,
+        });
+
         let raw = unsafe {
-            glue::NewJSAutoStructuredCloneBuffer(scope, callbacks as *const _)
+            glue::NewJSAutoStructuredCloneBuffer(scope, &*vtable as *const _)
         };
         assert!(!raw.is_null());
+
         StructuredCloneBuffer {
             raw: raw,
+            vtable: vtable,
+            closure: closure,
         }
     }
 
@@ -51,28 +167,87 @@ impl StructuredCloneBuffer {
         vec
     }
 
-    /// Read a JS value out of this buffer. Returns false when an underlying
-    /// JSAPI call fails.
-    pub fn read(&mut self,
-                vp: jsapi::JS::MutableHandleValue,
-                callbacks: &jsapi::JSStructuredCloneCallbacks)
-                -> bool {
+    /// Read a JS value out of this buffer, dispatching to this buffer's
+    /// `StructuredCloneCallbacks` for any tags it doesn't understand
+    /// natively. Returns false when an underlying JSAPI call fails.
+    pub fn read(&mut self, vp: jsapi::JS::MutableHandleValue) -> bool {
         unsafe {
-            (*self.raw).read(Runtime::get(), vp, callbacks as *const _, ptr::null_mut())
+            (*self.raw).read(Runtime::get(),
+                              vp,
+                              &*self.vtable as *const _,
+                              self.closure as *mut c_void)
         }
     }
 
-    /// Write a JS value into this buffer. Returns false when an underlying
-    /// JSAPI call fails.
-    pub fn write(&mut self,
-                 v: jsapi::JS::HandleValue,
-                 callbacks: &jsapi::JSStructuredCloneCallbacks)
-                 -> bool {
+    /// Read a JS value out of this buffer, applying the given
+    /// `JS::CloneDataPolicy` (e.g. to allow or forbid transferring
+    /// `SharedArrayBuffer`s across agent clusters). Returns false when an
+    /// underlying JSAPI call fails.
+    pub fn read_with_policy(&mut self,
+                             vp: jsapi::JS::MutableHandleValue,
+                             policy: &jsapi::JS::CloneDataPolicy)
+                             -> bool {
         unsafe {
-            (*self.raw).write(Runtime::get(), v, callbacks as *const _, ptr::null_mut())
+            (*self.raw).read(Runtime::get(),
+                              vp,
+                              policy as *const _,
+                              &*self.vtable as *const _,
+                              self.closure as *mut c_void)
         }
     }
 
+    /// Write a JS value into this buffer, dispatching to this buffer's
+    /// `StructuredCloneCallbacks` for any objects it doesn't understand
+    /// natively. Returns false when an underlying JSAPI call fails.
+    ///
+    /// This inherent method shadows `<Self as io::Write>::write` (bytes in,
+    /// `io::Result<usize>` out, see below): `buffer.write(x)` always
+    /// resolves here. Reach the `io::Write` impl explicitly, e.g.
+    /// `io::Write::write(&mut buffer, bytes)` or through a `W: io::Write`
+    /// bound.
+    pub fn write(&mut self, v: jsapi::JS::HandleValue) -> bool {
+        unsafe {
+            (*self.raw).write(Runtime::get(),
+                               v,
+                               &*self.vtable as *const _,
+                               self.closure as *mut c_void)
+        }
+    }
+
+    /// Write a JS value into this buffer, transferring ownership of the
+    /// objects listed in `transfer` (a JS `Array` of transferables) rather
+    /// than copying them. `policy` is applied as in `read_with_policy`.
+    /// Transferred objects are left neutered; see `transferred_objects`.
+    /// Returns false when an underlying JSAPI call fails.
+    pub fn write_with_transfer(&mut self,
+                                v: jsapi::JS::HandleValue,
+                                transfer: jsapi::JS::HandleValue,
+                                policy: &jsapi::JS::CloneDataPolicy)
+                                -> bool {
+        unsafe {
+            (*self.raw).write(Runtime::get(),
+                               v,
+                               transfer,
+                               policy as *const _,
+                               &*self.vtable as *const _,
+                               self.closure as *mut c_void)
+        }
+    }
+
+    /// The objects actually transferred out by the most recent call to
+    /// `write_with_transfer`. Empty if nothing has been transferred.
+    pub fn transferred_objects(&self) -> Vec<*mut jsapi::JSObject> {
+        let len = unsafe {
+            glue::GetLengthOfJSStructuredCloneTransferredObjects(self.raw)
+        };
+        let mut objects = Vec::with_capacity(len);
+        unsafe {
+            glue::GetJSStructuredCloneTransferredObjects(self.raw, objects.as_mut_ptr());
+            objects.set_len(len);
+        }
+        objects
+    }
+
     /// Copy the given slice into this buffer. Returns false when an underlying
     /// JSAPI call fails.
     pub fn write_bytes(&mut self, bytes: &[u8]) -> bool {
@@ -82,12 +257,93 @@ impl StructuredCloneBuffer {
             glue::WriteBytesToJSStructuredCloneData(src, len, self.data())
         }
     }
+
+    /// Borrow a `std::io::Read` over this buffer's data that walks the
+    /// underlying `JSStructuredCloneData`'s internal segments in place,
+    /// without copying them into an intermediate `Vec` the way
+    /// `copy_to_vec` does.
+    pub fn reader(&self) -> StructuredCloneReader {
+        StructuredCloneReader {
+            data: self.data(),
+            segment: 0,
+            offset: 0,
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// `StructuredCloneBuffer` also has an inherent `write` (a JS-value writer,
+/// see above) that takes priority over this trait method in method-call
+/// resolution; call through `io::Write::write(&mut buffer, bytes)` or a
+/// generic `W: io::Write` bound to reach this impl instead.
+impl io::Write for StructuredCloneBuffer {
+    /// Append `buf` to this buffer's data. Always reports the whole slice
+    /// as written; fails with `io::ErrorKind::Other` when the underlying
+    /// JSAPI call fails.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.write_bytes(buf) {
+            Ok(buf.len())
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other,
+                                "WriteBytesToJSStructuredCloneData failed"))
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A borrowing `std::io::Read` over a `StructuredCloneBuffer`'s data,
+/// walking the `JSStructuredCloneData`'s internal buffer segments directly
+/// instead of copying them up front.
+pub struct StructuredCloneReader<'a> {
+    data: *mut jsapi::JSStructuredCloneData,
+    segment: usize,
+    offset: usize,
+    _marker: PhantomData<&'a StructuredCloneBuffer>,
+}
+
+impl<'a> io::Read for StructuredCloneReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut total = 0;
+        while total < buf.len() {
+            let mut segment_ptr: *const u8 = ptr::null();
+            let mut segment_len: usize = 0;
+            let has_segment = unsafe {
+                glue::GetJSStructuredCloneDataSegment(self.data,
+                                                       self.segment,
+                                                       &mut segment_ptr,
+                                                       &mut segment_len)
+            };
+            if !has_segment {
+                break;
+            }
+
+            if self.offset >= segment_len {
+                self.segment += 1;
+                self.offset = 0;
+                continue;
+            }
+
+            let available = segment_len - self.offset;
+            let n = ::std::cmp::min(available, buf.len() - total);
+            unsafe {
+                let src = segment_ptr.add(self.offset);
+                ptr::copy_nonoverlapping(src, buf[total..].as_mut_ptr(), n);
+            }
+            self.offset += n;
+            total += n;
+        }
+        Ok(total)
+    }
 }
 
 impl Drop for StructuredCloneBuffer {
     fn drop(&mut self) {
         unsafe {
             glue::DeleteJSAutoStructuredCloneBuffer(self.raw);
+            drop(Box::from_raw(self.closure));
         }
     }
 }
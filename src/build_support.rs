@@ -0,0 +1,224 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pure helper logic for `build.rs`, factored out into its own module so
+//! it's exercised by `cargo test` against the crate's normal lib target.
+//! `build.rs` includes this file directly (via `#[path]`) rather than
+//! depending on the lib — build scripts are their own compilation unit and
+//! can't link against the crate they're building — so `#[cfg(test)]`
+//! blocks here run the way they would in any other module, unlike ones
+//! left inside `build.rs` itself, which `cargo test` never compiles.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Splits the contents of a `MOZJS_EXTRA_BINDGEN_FLAGS` file into individual
+/// `clang_arg`s.
+pub fn parse_bindgen_flags(contents: &str) -> Vec<String> {
+    contents.split_whitespace().map(|flag| flag.to_owned()).collect()
+}
+
+/// Extracts the `version` of the `[[package]]` entry named `name` from the
+/// contents of a `Cargo.lock` file.
+pub fn parse_locked_package_version(lockfile: &str, name: &str) -> Option<String> {
+    let mut lines = lockfile.lines();
+    let needle = format!("name = \"{}\"", name);
+    while let Some(line) = lines.next() {
+        if line.trim() != needle {
+            continue;
+        }
+        for line in lines.by_ref() {
+            let line = line.trim();
+            if line.starts_with('[') {
+                break;
+            }
+            if let Some(rest) = line.strip_prefix("version = \"") {
+                return rest.trim_end_matches('"').to_owned().into();
+            }
+        }
+    }
+    None
+}
+
+/// A cache key capturing everything that makes the generated `jsapi.rs`
+/// differ between configurations: the target, `cc_flags` (which is what
+/// `debugmozjs` toggles), the `extra_flags` read from
+/// `MOZJS_EXTRA_BINDGEN_FLAGS`, the contents of `JSGLUE_HEADER`, the locked
+/// `bindgen` version (see `bindgen_version` in `build.rs`), and a hash of
+/// `build.rs`/`build_support.rs`'s own bindgen config (see
+/// `build_config_hash` in `build.rs`).
+pub fn bindings_cache_key(
+    target: &str,
+    cc_flags: &[&str],
+    extra_flags: &[String],
+    bindgen_version: &str,
+    jsglue_hash: u64,
+    build_config_hash: u64,
+) -> String {
+    let mut hasher = DefaultHasher::new();
+    bindgen_version.hash(&mut hasher);
+    target.hash(&mut hasher);
+    for flag in cc_flags {
+        flag.hash(&mut hasher);
+    }
+    for flag in extra_flags {
+        flag.hash(&mut hasher);
+    }
+    jsglue_hash.hash(&mut hasher);
+    build_config_hash.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Enums matching any of these patterns are flag-style (combined bitwise
+/// by the C++ side) and so get `constified_enum_module` treatment instead
+/// of `rustified_enum(".*")`; see `build_jsapi_bindings` in `build.rs`.
+pub const CONSTIFIED_ENUM_PATTERNS: &'static [&'static str] = &[
+    "JSITER_.*",
+    "JSPROP_.*",
+    "JSFUN_.*",
+    "JSCLASS_.*",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        bindings_cache_key, parse_bindgen_flags, parse_locked_package_version,
+        CONSTIFIED_ENUM_PATTERNS,
+    };
+    use regex::RegexSet;
+
+    #[test]
+    fn splits_on_whitespace() {
+        assert_eq!(
+            parse_bindgen_flags("--sysroot=/foo -I/bar\n-DBAZ=1"),
+            vec!["--sysroot=/foo", "-I/bar", "-DBAZ=1"],
+        );
+    }
+
+    #[test]
+    fn empty_contents_is_empty() {
+        assert!(parse_bindgen_flags("   \n  ").is_empty());
+    }
+
+    #[test]
+    fn cache_key_changes_with_extra_flags() {
+        let without_extra = bindings_cache_key("x86_64-unknown-linux-gnu", &[], &[], "0.29.0", 0, 0);
+        let with_extra = bindings_cache_key(
+            "x86_64-unknown-linux-gnu",
+            &[],
+            &["-DSOME_FLAG".to_owned()],
+            "0.29.0",
+            0,
+            0,
+        );
+        assert_ne!(without_extra, with_extra);
+    }
+
+    #[test]
+    fn cache_key_changes_with_different_extra_flags() {
+        let a = bindings_cache_key(
+            "x86_64-unknown-linux-gnu",
+            &[],
+            &["-DFOO".to_owned()],
+            "0.29.0",
+            0,
+            0,
+        );
+        let b = bindings_cache_key(
+            "x86_64-unknown-linux-gnu",
+            &[],
+            &["-DBAR".to_owned()],
+            "0.29.0",
+            0,
+            0,
+        );
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn cache_key_changes_with_cc_flags() {
+        let a = bindings_cache_key("x86_64-unknown-linux-gnu", &["-DFOO"], &[], "0.29.0", 0, 0);
+        let b = bindings_cache_key("x86_64-unknown-linux-gnu", &["-DBAR"], &[], "0.29.0", 0, 0);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn cache_key_changes_with_bindgen_version() {
+        let a = bindings_cache_key("x86_64-unknown-linux-gnu", &[], &[], "0.29.0", 0, 0);
+        let b = bindings_cache_key("x86_64-unknown-linux-gnu", &[], &[], "0.30.0", 0, 0);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn cache_key_changes_with_jsglue_hash() {
+        let a = bindings_cache_key("x86_64-unknown-linux-gnu", &[], &[], "0.29.0", 1, 0);
+        let b = bindings_cache_key("x86_64-unknown-linux-gnu", &[], &[], "0.29.0", 2, 0);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn cache_key_changes_with_build_config_hash() {
+        let a = bindings_cache_key("x86_64-unknown-linux-gnu", &[], &[], "0.29.0", 0, 1);
+        let b = bindings_cache_key("x86_64-unknown-linux-gnu", &[], &[], "0.29.0", 0, 2);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn cache_key_stable_for_same_inputs() {
+        let extra = vec!["-DSOME_FLAG".to_owned()];
+        let a = bindings_cache_key("x86_64-unknown-linux-gnu", &["-DFOO"], &extra, "0.29.0", 42, 7);
+        let b = bindings_cache_key("x86_64-unknown-linux-gnu", &["-DFOO"], &extra, "0.29.0", 42, 7);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn parses_version_for_named_package() {
+        let lockfile = r#"
+[[package]]
+name = "bindgen"
+version = "0.29.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "cc"
+version = "1.0.0"
+"#;
+        assert_eq!(
+            parse_locked_package_version(lockfile, "bindgen"),
+            Some("0.29.0".to_owned()),
+        );
+    }
+
+    #[test]
+    fn parse_version_returns_none_for_missing_package() {
+        let lockfile = r#"
+[[package]]
+name = "cc"
+version = "1.0.0"
+"#;
+        assert_eq!(parse_locked_package_version(lockfile, "bindgen"), None);
+    }
+
+    #[test]
+    fn constified_enum_patterns_are_valid_regexes() {
+        RegexSet::new(CONSTIFIED_ENUM_PATTERNS)
+            .expect("CONSTIFIED_ENUM_PATTERNS should be valid regexes");
+    }
+
+    #[test]
+    fn constified_enum_patterns_match_flag_enums() {
+        let set = RegexSet::new(CONSTIFIED_ENUM_PATTERNS).unwrap();
+        assert!(set.is_match("JSITER_OWNONLY"));
+        assert!(set.is_match("JSPROP_ENUMERATE"));
+        assert!(set.is_match("JSFUN_CONSTRUCTOR"));
+        assert!(set.is_match("JSCLASS_GLOBAL_FLAGS"));
+    }
+
+    #[test]
+    fn constified_enum_patterns_do_not_match_closed_enums() {
+        let set = RegexSet::new(CONSTIFIED_ENUM_PATTERNS).unwrap();
+        assert!(!set.is_match("JSType"));
+        assert!(!set.is_match("JSProtoKey"));
+    }
+}
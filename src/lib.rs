@@ -0,0 +1,17 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Raw FFI bindings to SpiderMonkey, generated into `OUT_DIR` by `build.rs`.
+
+#[cfg(test)]
+extern crate regex;
+
+// Shared with `build.rs`, which includes this same file via `#[path]`
+// since build scripts can't depend on the lib they build. Its functions
+// have no caller in the lib target itself, only in build.rs and its own
+// `#[cfg(test)]` blocks.
+#[allow(dead_code)]
+mod build_support;
+
+include!(concat!(env!("OUT_DIR"), "/jsapi.rs"));